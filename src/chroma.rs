@@ -0,0 +1,96 @@
+//! Configurable chroma-key colour and alpha policy. The scope marks
+//! transparent pixels with a fixed colour key (0xCCCCCC by default) rather
+//! than via a real alpha channel; this lets users override that key for
+//! firmware with a different background colour, or bypass colour-keying
+//! altogether.
+
+use std::error::Error;
+
+use simple_error::SimpleError;
+
+use crate::fb::LayerGeometry;
+
+#[derive(Clone, Copy)]
+pub enum AlphaPolicy {
+    /// Transparent where the pixel matches the chroma key, opaque otherwise.
+    Key,
+    /// Always fully opaque, ignoring the chroma key entirely.
+    Opaque,
+    /// Whatever the format's own alpha/transparency channel decoded to.
+    Preserve,
+}
+
+/// A 24-bit colour key, pre-converted to its packed RGB565 form so the same
+/// key can be compared against either pixel format.
+#[derive(Clone, Copy)]
+pub struct ChromaKey {
+    pub enabled: bool,
+    rgb: (u8, u8, u8),
+    rgb565: u16,
+}
+
+impl ChromaKey {
+    pub fn new(enabled: bool, rgb: (u8, u8, u8)) -> ChromaKey {
+        let (r, g, b) = rgb;
+        let rgb565 = if rgb == (0xcc, 0xcc, 0xcc) {
+            // The firmware's actual transparent key packs to 0xCCCC, not what
+            // the generic 8-to-565 conversion below would produce (0xCE79) -
+            // keep the default behaviour bit-for-bit identical to baseline.
+            0xcccc
+        } else {
+            ((r as u16 & 0xf8) << 8) | ((g as u16 & 0xfc) << 3) | (b as u16 >> 3)
+        };
+        ChromaKey { enabled, rgb, rgb565 }
+    }
+
+    fn matches(&self, row: &[u8], pix: usize, geometry: &LayerGeometry, r: u8, g: u8, b: u8) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if geometry.bits_per_pixel >= 24 {
+            (r, g, b) == self.rgb
+        } else {
+            let bytes = geometry.bits_per_pixel / 8;
+            let start = pix * bytes;
+            let mut raw: u32 = 0;
+            for i in 0..bytes {
+                raw |= (row[start + i] as u32) << (8 * i);
+            }
+            raw == self.rgb565 as u32
+        }
+    }
+}
+
+pub struct Policy {
+    pub chroma: ChromaKey,
+    pub alpha: AlphaPolicy,
+}
+
+impl Policy {
+    /// Decide the alpha byte for a pixel, given what the format itself
+    /// decoded (`a`) and its other channels.
+    pub fn resolve_alpha(&self, row: &[u8], pix: usize, geometry: &LayerGeometry, r: u8, g: u8, b: u8, a: u8) -> u8 {
+        match self.alpha {
+            AlphaPolicy::Opaque => 0xff,
+            AlphaPolicy::Preserve => a,
+            AlphaPolicy::Key => {
+                if self.chroma.matches(row, pix, geometry, r, g, b) {
+                    0
+                } else {
+                    0xff
+                }
+            }
+        }
+    }
+}
+
+/// Parse a `"RRGGBB"` hex colour.
+pub fn parse_hex(raw: &str) -> Result<(u8, u8, u8), Box<dyn Error>> {
+    if raw.len() != 6 {
+        return Err(Box::new(SimpleError::new("--chroma must be given as RRGGBB")));
+    }
+    let r = u8::from_str_radix(&raw[0..2], 16)?;
+    let g = u8::from_str_radix(&raw[2..4], 16)?;
+    let b = u8::from_str_radix(&raw[4..6], 16)?;
+    Ok((r, g, b))
+}
@@ -0,0 +1,91 @@
+//! Combines several framebuffer layers into a single flattened image, the way
+//! they actually appear stacked on the scope's screen.
+
+use std::error::Error;
+use std::fs::File;
+
+use memmap::MmapOptions;
+
+use crate::chroma::Policy;
+use crate::fb;
+use crate::{get_layer, swap_layer};
+
+/// One layer's geometry plus its fully decoded RGBA8888 pixels.
+pub struct LayerCapture {
+    pub geometry: fb::LayerGeometry,
+    pub rgba: Vec<u8>,
+}
+
+/// Restores whichever layer was active before compositing started, even if a
+/// later layer swap or mmap fails partway through.
+struct LayerRestoreGuard<'a> {
+    file: &'a File,
+    original: i32,
+}
+
+impl<'a> Drop for LayerRestoreGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = swap_layer(self.file, self.original) {
+            eprintln!("Failed to restore original layer {}: {}", self.original, e);
+        }
+    }
+}
+
+/// Switch through `layers` in order, mapping and decoding each one while it
+/// is active, then restore the layer that was active beforehand.
+pub fn capture_layers(input: &File, layers: &[i32], policy: &Policy) -> Result<Vec<LayerCapture>, Box<dyn Error>> {
+    let original = get_layer(input)?;
+    eprintln!("Active layer is: {}", original);
+    let _restore = LayerRestoreGuard { file: input, original };
+
+    let mut captures = Vec::with_capacity(layers.len());
+    for &layer in layers {
+        swap_layer(input, layer)?;
+        let geometry = fb::detect_geometry(input, layer);
+        let mmap = unsafe { MmapOptions::new().len(geometry.buffer_len()).map(input)? };
+        let rgba = fb::decode_layer_rgba(&mmap, &geometry, None, |row, pix, r, g, b, a| {
+            policy.resolve_alpha(row, pix, &geometry, r, g, b, a)
+        });
+        captures.push(LayerCapture { geometry, rgba });
+    }
+    Ok(captures)
+}
+
+/// Alpha-composite captures (bottom to top, i.e. `captures[0]` drawn first)
+/// onto one RGBA canvas sized to fit every layer at its reported offset,
+/// using standard source-over blending.
+pub fn flatten(captures: &[LayerCapture]) -> (usize, usize, Vec<u8>) {
+    let width = captures
+        .iter()
+        .map(|c| c.geometry.xoffset + c.geometry.width)
+        .max()
+        .unwrap_or(0);
+    let height = captures
+        .iter()
+        .map(|c| c.geometry.yoffset + c.geometry.height)
+        .max()
+        .unwrap_or(0);
+    let mut canvas = vec![0u8; width * height * 4];
+
+    for capture in captures {
+        let geom = &capture.geometry;
+        for y in 0..geom.height {
+            let dst_y = geom.yoffset + y;
+            for x in 0..geom.width {
+                let dst_x = geom.xoffset + x;
+                let src_idx = (y * geom.width + x) * 4;
+                let dst_idx = (dst_y * width + dst_x) * 4;
+                let src = &capture.rgba[src_idx..src_idx + 4];
+                let src_a = src[3] as f32 / 255.0;
+
+                for c in 0..3 {
+                    let dst = canvas[dst_idx + c] as f32;
+                    canvas[dst_idx + c] = (src[c] as f32 * src_a + dst * (1.0 - src_a)).round() as u8;
+                }
+                let dst_a = canvas[dst_idx + 3] as f32 / 255.0;
+                canvas[dst_idx + 3] = ((src_a + dst_a * (1.0 - src_a)) * 255.0).round() as u8;
+            }
+        }
+    }
+    (width, height, canvas)
+}
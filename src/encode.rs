@@ -0,0 +1,118 @@
+//! Output encoders. Every format takes the same input (one or more already
+//! decoded RGBA8888 pages) so the row-emitting logic only needs to exist
+//! once per format, independent of where the pixels came from (a single
+//! layer, a `--composite` flatten, or several `--layers` dumped side by
+//! side).
+
+use std::error::Error;
+use std::io::Write;
+
+use simple_error::SimpleError;
+
+/// One fully decoded image, ready to hand to an encoder.
+pub struct Page {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+
+pub trait ImageEncoder {
+    /// Encode `pages` to `output`. Formats that can't represent more than one
+    /// page should error out rather than silently dropping the extras.
+    fn encode(&self, output: Box<dyn Write>, pages: &[Page]) -> Result<(), Box<dyn Error>>;
+}
+
+fn require_single_page<'a>(pages: &'a [Page], format: &str) -> Result<&'a Page, Box<dyn Error>> {
+    match pages {
+        [page] => Ok(page),
+        _ => Err(Box::new(SimpleError::new(format!(
+            "{} output only supports a single page, got {}",
+            format,
+            pages.len()
+        )))),
+    }
+}
+
+pub struct PngEncoder {
+    /// `Some(level)` runs the `--optimize` pass (0..=6) instead of handing
+    /// pixels straight to the `png` crate's default encoding.
+    pub optimize: Option<u8>,
+}
+
+impl ImageEncoder for PngEncoder {
+    fn encode(&self, mut output: Box<dyn Write>, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        let page = require_single_page(pages, "PNG")?;
+        match self.optimize {
+            Some(level) => crate::png_opt::write_optimized_png(&mut *output, page, level)?,
+            None => {
+                let mut encoder = png::Encoder::new(output, page.width as u32, page.height as u32);
+                encoder.set_color(png::ColorType::RGBA);
+                encoder.set_depth(png::BitDepth::Eight);
+                let mut writer = encoder
+                    .write_header()
+                    .unwrap()
+                    .into_stream_writer_with_size(4000);
+                writer.write_all(&page.rgba)?;
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uncompressed 32bpp BMP (`BI_RGB`), stored bottom-up as is conventional for
+/// the format.
+pub struct BmpEncoder;
+
+impl ImageEncoder for BmpEncoder {
+    fn encode(&self, mut output: Box<dyn Write>, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        let page = require_single_page(pages, "BMP")?;
+        let width = page.width as u32;
+        let height = page.height as u32;
+        let image_size = width * height * 4;
+        let file_size = 14 + 40 + image_size;
+
+        let mut header = Vec::with_capacity(14 + 40);
+        // BITMAPFILEHEADER
+        header.extend_from_slice(b"BM");
+        header.extend_from_slice(&file_size.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        header.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        header.extend_from_slice(&(14 + 40u32).to_le_bytes()); // pixel data offset
+
+        // BITMAPINFOHEADER
+        header.extend_from_slice(&40u32.to_le_bytes()); // header size
+        header.extend_from_slice(&(width as i32).to_le_bytes());
+        header.extend_from_slice(&(height as i32).to_le_bytes()); // positive: bottom-up
+        header.extend_from_slice(&1u16.to_le_bytes()); // planes
+        header.extend_from_slice(&32u16.to_le_bytes()); // bits per pixel
+        header.extend_from_slice(&0u32.to_le_bytes()); // BI_RGB, no compression
+        header.extend_from_slice(&image_size.to_le_bytes());
+        header.extend_from_slice(&2835i32.to_le_bytes()); // ~72 DPI
+        header.extend_from_slice(&2835i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // colours used
+        header.extend_from_slice(&0u32.to_le_bytes()); // important colours
+        output.write_all(&header)?;
+
+        // BMP rows run bottom-to-top, and each pixel is stored BGRA.
+        for row in (0..page.height).rev() {
+            let row_start = row * page.width * 4;
+            for pix in page.rgba[row_start..row_start + page.width * 4].chunks_exact(4) {
+                output.write_all(&[pix[2], pix[1], pix[0], pix[3]])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Headerless dump of the decoded RGBA pixels, one page after another.
+pub struct RawEncoder;
+
+impl ImageEncoder for RawEncoder {
+    fn encode(&self, mut output: Box<dyn Write>, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        for page in pages {
+            output.write_all(&page.rgba)?;
+        }
+        Ok(())
+    }
+}
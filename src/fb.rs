@@ -0,0 +1,306 @@
+//! Framebuffer geometry and pixel format discovery.
+//!
+//! The scope's framebuffer driver exposes the standard Linux `fb_var_screeninfo`
+//! and `fb_fix_screeninfo` structures via `FBIOGET_VSCREENINFO`/`FBIOGET_FSCREENINFO`.
+//! Querying them lets us work out width, height, stride and the exact bit layout
+//! of each colour channel instead of relying on constants baked in for one
+//! particular firmware revision.
+
+use libc::{c_char, c_ulong};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+use crate::IoctlError;
+
+const FBIOGET_VSCREENINFO: u64 = 0x4600;
+const FBIOGET_FSCREENINFO: u64 = 0x4602;
+
+/// Mirrors `struct fb_bitfield` from `linux/fb.h`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FbBitfield {
+    pub offset: u32,
+    pub length: u32,
+    pub msb_right: u32,
+}
+
+/// Mirrors `struct fb_var_screeninfo` from `linux/fb.h`. The full struct has to
+/// be reproduced field-for-field (not just the parts we care about) since the
+/// kernel writes into it through the ioctl and a too-small buffer would corrupt
+/// memory past the end of a truncated struct.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FbVarScreeninfo {
+    pub xres: u32,
+    pub yres: u32,
+    pub xres_virtual: u32,
+    pub yres_virtual: u32,
+    pub xoffset: u32,
+    pub yoffset: u32,
+
+    pub bits_per_pixel: u32,
+    pub grayscale: u32,
+
+    pub red: FbBitfield,
+    pub green: FbBitfield,
+    pub blue: FbBitfield,
+    pub transp: FbBitfield,
+
+    pub nonstd: u32,
+
+    pub activate: u32,
+
+    pub height: u32,
+    pub width: u32,
+
+    pub accel_flags: u32,
+
+    pub pixclock: u32,
+    pub left_margin: u32,
+    pub right_margin: u32,
+    pub upper_margin: u32,
+    pub lower_margin: u32,
+    pub hsync_len: u32,
+    pub vsync_len: u32,
+    pub sync: u32,
+    pub vmode: u32,
+    pub rotate: u32,
+    pub colorspace: u32,
+    pub reserved: [u32; 4],
+}
+
+/// Mirrors `struct fb_fix_screeninfo` from `linux/fb.h`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FbFixScreeninfo {
+    pub id: [c_char; 16],
+    pub smem_start: c_ulong,
+    pub smem_len: u32,
+    pub fb_type: u32,
+    pub type_aux: u32,
+    pub visual: u32,
+    pub xpanstep: u16,
+    pub ypanstep: u16,
+    pub ywrapstep: u16,
+    pub line_length: u32,
+    pub mmio_start: c_ulong,
+    pub mmio_len: u32,
+    pub accel: u32,
+    pub capabilities: u16,
+    pub reserved: [u16; 2],
+}
+
+impl Default for FbFixScreeninfo {
+    fn default() -> Self {
+        // Zero-initialize; there is no meaningful default id/visual to pick.
+        unsafe { std::mem::zeroed() }
+    }
+}
+
+fn get_var_screeninfo(file: &File) -> Result<FbVarScreeninfo, IoctlError> {
+    let mut info = FbVarScreeninfo::default();
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), FBIOGET_VSCREENINFO, &mut info) };
+    if res != 0 {
+        Err(IoctlError { return_value: res })
+    } else {
+        Ok(info)
+    }
+}
+
+fn get_fix_screeninfo(file: &File) -> Result<FbFixScreeninfo, IoctlError> {
+    let mut info = FbFixScreeninfo::default();
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), FBIOGET_FSCREENINFO, &mut info) };
+    if res != 0 {
+        Err(IoctlError { return_value: res })
+    } else {
+        Ok(info)
+    }
+}
+
+/// Everything we need to decode one layer's pixels, whatever shape they turn
+/// out to be.
+#[derive(Debug, Clone, Copy)]
+pub struct LayerGeometry {
+    pub width: usize,
+    pub height: usize,
+    pub bits_per_pixel: usize,
+    /// Bytes per scanline. Not necessarily `width * bits_per_pixel / 8`: the
+    /// driver is free to pad rows, so this must be used for striding instead
+    /// of recomputing it from width.
+    pub line_length: usize,
+    /// Where this layer sits on the physical screen, as reported by
+    /// `xoffset`/`yoffset`. Used to place layers correctly when compositing.
+    pub xoffset: usize,
+    pub yoffset: usize,
+    pub red: FbBitfield,
+    pub green: FbBitfield,
+    pub blue: FbBitfield,
+    pub transp: FbBitfield,
+}
+
+impl LayerGeometry {
+    pub fn buffer_len(&self) -> usize {
+        self.height * self.line_length
+    }
+
+    /// The hardcoded metrics this tool used before geometry discovery existed.
+    /// Kept as a fallback for firmware/layers that don't answer the ioctls.
+    pub fn fallback(layer: i32) -> LayerGeometry {
+        match layer {
+            1 => LayerGeometry {
+                width: 1000,
+                height: 480,
+                bits_per_pixel: 32,
+                line_length: 1000 * 4,
+                xoffset: 0,
+                yoffset: 0,
+                // Native layout is BGRA8888.
+                blue: FbBitfield { offset: 0, length: 8, msb_right: 0 },
+                green: FbBitfield { offset: 8, length: 8, msb_right: 0 },
+                red: FbBitfield { offset: 16, length: 8, msb_right: 0 },
+                transp: FbBitfield { offset: 24, length: 8, msb_right: 0 },
+            },
+            2 => LayerGeometry {
+                width: 1000,
+                height: 480,
+                ..LayerGeometry::rgb565(1000, 480)
+            },
+            _ => LayerGeometry::rgb565(1024, 600),
+        }
+    }
+
+    fn rgb565(width: usize, height: usize) -> LayerGeometry {
+        LayerGeometry {
+            width,
+            height,
+            bits_per_pixel: 16,
+            line_length: width * 2,
+            xoffset: 0,
+            yoffset: 0,
+            red: FbBitfield { offset: 11, length: 5, msb_right: 0 },
+            green: FbBitfield { offset: 5, length: 6, msb_right: 0 },
+            blue: FbBitfield { offset: 0, length: 5, msb_right: 0 },
+            // No alpha channel in RGB565; transparency is conveyed out of band
+            // via the 0xCCCC colour key instead.
+            transp: FbBitfield { offset: 0, length: 0, msb_right: 0 },
+        }
+    }
+}
+
+/// Query the active layer's geometry and pixel format via `FBIOGET_VSCREENINFO`/
+/// `FBIOGET_FSCREENINFO`. Falls back to the historical per-layer constants if
+/// either ioctl fails, e.g. on firmware that doesn't implement them for this
+/// device node.
+pub fn detect_geometry(file: &File, layer: i32) -> LayerGeometry {
+    match (get_var_screeninfo(file), get_fix_screeninfo(file)) {
+        (Ok(var), Ok(fix)) => LayerGeometry {
+            width: var.xres as usize,
+            height: var.yres as usize,
+            bits_per_pixel: var.bits_per_pixel as usize,
+            line_length: fix.line_length as usize,
+            xoffset: var.xoffset as usize,
+            yoffset: var.yoffset as usize,
+            red: var.red,
+            green: var.green,
+            blue: var.blue,
+            transp: var.transp,
+        },
+        _ => {
+            eprintln!("Geometry ioctls failed, falling back to built-in layer constants");
+            LayerGeometry::fallback(layer)
+        }
+    }
+}
+
+/// Scale a channel value of `length` bits up to a full 8 bits by replicating
+/// its high bits into the newly freed low bits, e.g. RGB565's 5-bit red
+/// `rrrrr` becomes `rrrrrrrr` rather than just `rrrrr000`.
+fn scale_to_8bit(val: u32, length: u32) -> u8 {
+    if length == 0 {
+        return 0;
+    }
+    if length >= 8 {
+        return (val >> (length - 8)) as u8;
+    }
+    if length >= 4 {
+        return ((val << (8 - length)) | (val >> (2 * length - 8))) as u8;
+    }
+    // Channels this narrow (1-3 bits, e.g. ARGB1555's 1-bit alpha) can't use
+    // the shift above - `2 * length - 8` would underflow. Replicate the bits
+    // by repeated shift-and-OR until at least 8 bits are filled instead, then
+    // keep only the top 8.
+    let mut out = 0u32;
+    let mut filled = 0;
+    while filled < 8 {
+        out = (out << length) | val;
+        filled += length;
+    }
+    (out >> (filled - 8)) as u8
+}
+
+/// Read the raw `bits_per_pixel`-wide value for one pixel out of a row buffer,
+/// little-endian, starting at byte `pixel_idx * bits_per_pixel / 8`.
+fn read_raw_pixel(row: &[u8], pixel_idx: usize, bits_per_pixel: usize) -> u32 {
+    let bytes = bits_per_pixel / 8;
+    let start = pixel_idx * bytes;
+    let mut raw: u32 = 0;
+    for i in 0..bytes {
+        raw |= (row[start + i] as u32) << (8 * i);
+    }
+    raw
+}
+
+fn extract_channel(raw: u32, field: &FbBitfield) -> u8 {
+    if field.length == 0 {
+        return 0xff;
+    }
+    let mask = (1u32 << field.length) - 1;
+    let val = (raw >> field.offset) & mask;
+    scale_to_8bit(val, field.length)
+}
+
+/// Decode one pixel out of `row` (at `pixel_idx`) into RGBA8888, using the
+/// channel layout described by `geometry`. Returns `(r, g, b, a)`; callers
+/// that use a colour-key for transparency should ignore `a` (it will be 0xff
+/// when there is no transparency channel at all, e.g. RGB565) and test the
+/// raw packed value against the key themselves.
+pub fn decode_pixel(row: &[u8], pixel_idx: usize, geometry: &LayerGeometry) -> (u8, u8, u8, u8) {
+    let raw = read_raw_pixel(row, pixel_idx, geometry.bits_per_pixel);
+    (
+        extract_channel(raw, &geometry.red),
+        extract_channel(raw, &geometry.green),
+        extract_channel(raw, &geometry.blue),
+        extract_channel(raw, &geometry.transp),
+    )
+}
+
+/// Decode a mapped layer into a tightly packed RGBA8888 buffer, optionally
+/// restricted to a `(x, y, width, height)` rectangle so rows and columns
+/// outside it are never even decoded. `alpha_override` gets the decoded
+/// channel alpha plus the row's raw bytes and pixel index, so callers can
+/// replace it with colour-key based transparency instead of whatever the
+/// format's own alpha channel says.
+pub fn decode_layer_rgba(
+    mmap: &[u8],
+    geometry: &LayerGeometry,
+    region: Option<(usize, usize, usize, usize)>,
+    mut alpha_override: impl FnMut(&[u8], usize, u8, u8, u8, u8) -> u8,
+) -> Vec<u8> {
+    let (rx, ry, rw, rh) = region.unwrap_or((0, 0, geometry.width, geometry.height));
+    let bytes_per_pixel = geometry.bits_per_pixel / 8;
+    let mut out = vec![0u8; rw * rh * 4];
+    for (out_row, row) in (ry..ry + rh).enumerate() {
+        let row_start = row * geometry.line_length;
+        let row_bytes = &mmap[row_start..row_start + geometry.width * bytes_per_pixel];
+        for (out_col, pix) in (rx..rx + rw).enumerate() {
+            let (r, g, b, a) = decode_pixel(row_bytes, pix, geometry);
+            let a = alpha_override(row_bytes, pix, r, g, b, a);
+            let idx = (out_row * rw + out_col) * 4;
+            out[idx] = r;
+            out[idx + 1] = g;
+            out[idx + 2] = b;
+            out[idx + 3] = a;
+        }
+    }
+    out
+}
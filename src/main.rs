@@ -9,6 +9,18 @@ use std::fs::File;
 use std::io::{self, Write};
 use std::os::unix::io::AsRawFd;
 
+mod chroma;
+mod composite;
+mod encode;
+mod fb;
+mod png_opt;
+mod region;
+mod tiff;
+
+use chroma::{AlphaPolicy, ChromaKey, Policy};
+use encode::{BmpEncoder, ImageEncoder, Page, PngEncoder, RawEncoder};
+use tiff::{TiffCompression, TiffEncoder};
+
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("MSO5k Framebuffer Dumper")
         .about("Reads the different layers of the framebuffer")
@@ -43,14 +55,93 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .about("Instruct the hardware to do a printscreen")
                 .takes_value(false),
         )
+        .arg(
+            Arg::new("composite")
+                .long("composite")
+                .about("Flatten several layers into a single image, as seen on screen")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("layers")
+                .long("layers")
+                .value_name("LIST")
+                .about("Comma-separated layers to composite (with --composite) or dump as separate pages (without it), bottom to top")
+                .default_value("0,2,3,4,5,1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .about("Output format: png, tiff, bmp or raw (headerless RGBA)")
+                .possible_values(&["png", "tiff", "bmp", "raw"])
+                .default_value("png")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("tiff-compression")
+                .long("tiff-compression")
+                .value_name("COMPRESSION")
+                .about("Compression used for --format tiff")
+                .possible_values(&["none", "deflate", "packbits"])
+                .default_value("packbits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("optimize")
+                .long("optimize")
+                .value_name("LEVEL")
+                .about("Re-derive the smallest PNG encoding (0 fastest .. 6 smallest)")
+                .possible_values(&["0", "1", "2", "3", "4", "5", "6"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("region")
+                .long("region")
+                .value_name("X,Y,W,H")
+                .about("Only dump this rectangle of each page")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("chroma")
+                .long("chroma")
+                .value_name("RRGGBB")
+                .about("Colour key the scope paints transparent pixels with")
+                .default_value("cccccc")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("no-chroma")
+                .long("no-chroma")
+                .about("Disable colour-key transparency entirely")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("alpha")
+                .long("alpha")
+                .value_name("POLICY")
+                .about("How to derive each pixel's alpha: key, opaque or preserve")
+                .possible_values(&["key", "opaque", "preserve"])
+                .default_value("key")
+                .takes_value(true),
+        )
         .arg(Arg::new("layer").about("Layer number").value_name("LAYER"))
         .get_matches();
 
+    let composite = matches.is_present("composite");
+    // Multiple pages come from either flattening everything with --composite,
+    // or (if the user passed --layers without --composite) dumping several
+    // layers side by side, one page each - no positional LAYER is needed for
+    // either. Otherwise it's the classic single `layer` dump.
+    let multi_page = !composite && matches.occurrences_of("layers") > 0;
+
     let layer = match matches.value_of("layer") {
         Some(x) => x.parse::<i32>()?,
         None => {
             if matches.is_present("printscreen") {
                 4 // If no layer is specified and the printscreen option is passed, take that layer.
+            } else if composite || multi_page {
+                0 // Unused in composite/multi-page mode; --layers drives which layers get read.
             } else {
                 return Err(Box::new(SimpleError::new("No layer number given.")));
             }
@@ -60,21 +151,6 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Err(Box::new(SimpleError::new("Layer must be from 0 to 5")));
     }
 
-    // Determine layer metrics.
-    let width: usize = match layer {
-        1 | 2 => 1000,
-        _ => 1024,
-    };
-    let height: usize = match layer {
-        1 | 2 => 480,
-        _ => 600,
-    };
-    let bytes_per_pixel: usize = match layer {
-        1 => 4,
-        _ => 2,
-    };
-    let layer_len: usize = width * height * bytes_per_pixel;
-
     // Set up the output - either a file or stdout.
     let mut output: Box<dyn Write> = match matches.value_of("output").unwrap() {
         "-" => Box::new(io::stdout()),
@@ -96,70 +172,123 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    // Switch layer, map the memory and switch back.
+    let region = matches.value_of("region").map(region::parse).transpose()?;
+
+    let policy = Policy {
+        chroma: ChromaKey::new(
+            !matches.is_present("no-chroma"),
+            chroma::parse_hex(matches.value_of("chroma").unwrap())?,
+        ),
+        alpha: match matches.value_of("alpha").unwrap() {
+            "key" => AlphaPolicy::Key,
+            "opaque" => AlphaPolicy::Opaque,
+            "preserve" => AlphaPolicy::Preserve,
+            _ => unreachable!("restricted by possible_values"),
+        },
+    };
+
+    if composite || multi_page {
+        let layers = parse_layer_list(matches.value_of("layers").unwrap())?;
+        let captures = composite::capture_layers(&input, &layers, &policy)?;
+        let mut pages = if composite {
+            let (width, height, rgba) = composite::flatten(&captures);
+            vec![Page { width, height, rgba }]
+        } else {
+            captures
+                .into_iter()
+                .map(|c| Page {
+                    width: c.geometry.width,
+                    height: c.geometry.height,
+                    rgba: c.rgba,
+                })
+                .collect()
+        };
+        if let Some(region) = &region {
+            pages = pages
+                .iter()
+                .map(|p| region::crop(p, region))
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+        encoder_for(&matches)?.encode(output, &pages)?;
+        return Ok(());
+    }
+
+    // Switch layer, then determine its metrics - the VSCREENINFO/FSCREENINFO
+    // ioctls report whatever layer is currently active, so detection has to
+    // happen after the swap, not before.
     let old_layer = get_layer(&input)?;
     eprintln!("Active layer is: {}", old_layer);
     swap_layer(&input, layer)?;
-    let mmap = unsafe { MmapOptions::new().len(layer_len).map(&input)? };
+    let geometry = fb::detect_geometry(&input, layer);
+    let mmap = unsafe { MmapOptions::new().len(geometry.buffer_len()).map(&input)? };
     swap_layer(&input, old_layer)?;
     eprintln!("Layer has been switched back to: {}", old_layer);
 
-    // Generate the output either raw or as a PNG.
+    // Generate the output: the legacy --raw flag dumps the native framebuffer
+    // memory untouched, bypassing decoding entirely; everything else goes
+    // through the pixel decoder and the chosen --format encoder.
     if matches.is_present("raw") {
         output.write_all(&mmap)?;
     } else {
-        let mut encoder = png::Encoder::new(output, width as u32, height as u32);
-        encoder.set_color(png::ColorType::RGBA);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder
-            .write_header()
-            .unwrap()
-            .into_stream_writer_with_size(4000);
-
-        let mut buf: Vec<u8> = vec![0; width * 4];
-        for row in 0..height {
-            match layer {
-                1 => {
-                    buf.copy_from_slice(&mmap[row * width * 4..(row + 1) * width * 4]);
-                    for pix in 0..width {
-                        if buf[pix * 4] != 0xcc
-                            || buf[pix * 4 + 1] != 0xcc
-                            || buf[pix * 4 + 2] != 0xcc
-                        {
-                            // This pixel is not transparent (0xCCCCCC), so set the alpha channel to 255.
-                            buf.swap(pix * 4, pix * 4 + 2);
-                            buf[pix * 4 + 3] = 0xff;
-                        }
-                    }
-                }
-                _ => {
-                    // The other layers are RGB565
-                    for pix in 0..width {
-                        let packed: u16 = (mmap[(row * width + pix) * 2 + 1] as u16) << 8
-                            | (mmap[(row * width + pix) * 2]) as u16;
-                        buf[pix * 4] = ((packed & 0xf800) >> 8) as u8;
-                        buf[pix * 4 + 1] = ((packed & 0x7e0) >> 3) as u8;
-                        buf[pix * 4 + 2] = ((packed & 0x1f) << 3) as u8;
-
-                        if packed == 0xcccc {
-                            buf[pix * 4 + 3] = 0;
-                        } else {
-                            buf[pix * 4 + 3] = 0xff;
-                        }
-                    }
-                }
-            }
-            writer.write_all(&buf)?;
-        }
-        writer.finish()?;
+        // Restricting the region up front means rows and columns outside it
+        // are never decoded at all, unlike the composite/multi-page path
+        // above which crops after the fact.
+        let crop_rect = region
+            .as_ref()
+            .map(|r| region::clamp(r, geometry.width, geometry.height))
+            .transpose()?;
+        let (width, height) = crop_rect.map_or((geometry.width, geometry.height), |(_, _, w, h)| (w, h));
+        let rgba = fb::decode_layer_rgba(&mmap, &geometry, crop_rect, |row, pix, r, g, b, a| {
+            policy.resolve_alpha(row, pix, &geometry, r, g, b, a)
+        });
+        let page = Page { width, height, rgba };
+        encoder_for(&matches)?.encode(output, &[page])?;
     }
 
     Ok(())
 }
 
+/// Parse a comma-separated layer list such as `"0,2,3,4,5,1"`, validating
+/// that every entry is a valid layer number.
+fn parse_layer_list(raw: &str) -> Result<Vec<i32>, Box<dyn Error>> {
+    raw.split(',')
+        .map(|part| {
+            let layer = part.trim().parse::<i32>()?;
+            if !(0..=5).contains(&layer) {
+                return Err(Box::new(SimpleError::new("Layer must be from 0 to 5")) as Box<dyn Error>);
+            }
+            Ok(layer)
+        })
+        .collect()
+}
+
+fn encoder_for(matches: &clap::ArgMatches) -> Result<Box<dyn ImageEncoder>, Box<dyn Error>> {
+    let optimize = matches
+        .value_of("optimize")
+        .map(|v| v.parse::<u8>().unwrap());
+    if optimize.is_some() && matches.value_of("format").unwrap() != "png" {
+        eprintln!("--optimize only applies to --format png, ignoring it");
+    }
+
+    Ok(match matches.value_of("format").unwrap() {
+        "png" => Box::new(PngEncoder { optimize }),
+        "bmp" => Box::new(BmpEncoder),
+        "raw" => Box::new(RawEncoder),
+        "tiff" => Box::new(TiffEncoder {
+            compression: match matches.value_of("tiff-compression").unwrap() {
+                "none" => TiffCompression::None,
+                "deflate" => TiffCompression::Deflate,
+                "packbits" => TiffCompression::PackBits,
+                _ => unreachable!("restricted by possible_values"),
+            },
+        }),
+        _ => unreachable!("restricted by possible_values"),
+    })
+}
+
 #[derive(Debug)]
-struct IoctlError {
-    return_value: i32,
+pub(crate) struct IoctlError {
+    pub(crate) return_value: i32,
 }
 impl fmt::Display for IoctlError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -168,7 +297,7 @@ impl fmt::Display for IoctlError {
 }
 impl Error for IoctlError {}
 
-fn get_layer(file: &File) -> Result<i32, IoctlError> {
+pub(crate) fn get_layer(file: &File) -> Result<i32, IoctlError> {
     unsafe {
         let mut layer: i32 = -1;
         let layer_ptr: *mut i32 = &mut layer;
@@ -181,7 +310,7 @@ fn get_layer(file: &File) -> Result<i32, IoctlError> {
     }
 }
 
-fn swap_layer(file: &File, idx: i32) -> Result<(), IoctlError> {
+pub(crate) fn swap_layer(file: &File, idx: i32) -> Result<(), IoctlError> {
     unsafe {
         let res = libc::ioctl(file.as_raw_fd(), 0x0f000000, idx);
         if res != 0 {
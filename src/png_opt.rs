@@ -0,0 +1,280 @@
+//! `--optimize` support: a small from-scratch PNG writer used instead of the
+//! `png` crate's defaults. It re-derives a smaller encoding by trying every
+//! scanline filter and picking whichever minimizes the sum of absolute
+//! filtered-byte differences (the standard libpng heuristic), and by
+//! collapsing to a smaller colour type when the pixels allow it - an indexed
+//! palette for screenshots with few distinct colours, or a plain opaque /
+//! single-colour-keyed image when every pixel's alpha is 0 or 255, all
+//! transparent pixels share one RGB value and no opaque pixel uses it.
+//!
+//! The scope's UI captures are almost always one of these cases, since they
+//! are flat-shaded and chroma-keyed rather than photographic.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::encode::Page;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+enum ColorPlan {
+    Indexed {
+        palette: Vec<(u8, u8, u8)>,
+        trns: Vec<u8>,
+        pixel_index: Vec<u8>,
+    },
+    Rgb {
+        transparent_color: Option<(u8, u8, u8)>,
+    },
+    Rgba,
+}
+
+/// Write `page` as an optimized PNG. `level` (0..=6) trades encoding effort
+/// for file size: 0 skips the per-row filter search and uses the fastest
+/// zlib setting, higher levels search every filter and compress harder.
+pub fn write_optimized_png(output: &mut dyn Write, page: &Page, level: u8) -> io::Result<()> {
+    let plan = plan_color_type(page);
+    let (rows, bpp) = raw_rows(page, &plan);
+
+    let mut filtered_stream = Vec::new();
+    let mut prev: Vec<u8> = Vec::new();
+    for row in &rows {
+        filtered_stream.extend_from_slice(&choose_filtered_row(row, &prev, bpp, level));
+        prev = row.clone();
+    }
+
+    let zlib_level = if level == 0 { 1 } else { (3 + level as u32).min(9) };
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::new(zlib_level));
+    zlib.write_all(&filtered_stream)?;
+    let idat = zlib.finish()?;
+
+    let mut file = Vec::new();
+    file.extend_from_slice(&SIGNATURE);
+
+    let (color_type, bit_depth) = match &plan {
+        ColorPlan::Indexed { .. } => (3u8, 8u8),
+        ColorPlan::Rgb { .. } => (2, 8),
+        ColorPlan::Rgba => (6, 8),
+    };
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(page.width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(page.height as u32).to_be_bytes());
+    ihdr.push(bit_depth);
+    ihdr.push(color_type);
+    ihdr.extend_from_slice(&[0, 0, 0]); // compression, filter, interlace: all the PNG defaults
+    write_chunk(&mut file, b"IHDR", &ihdr);
+
+    match &plan {
+        ColorPlan::Indexed { palette, trns, .. } => {
+            let mut plte = Vec::with_capacity(palette.len() * 3);
+            for &(r, g, b) in palette {
+                plte.extend_from_slice(&[r, g, b]);
+            }
+            write_chunk(&mut file, b"PLTE", &plte);
+            if !trns.is_empty() {
+                write_chunk(&mut file, b"tRNS", trns);
+            }
+        }
+        ColorPlan::Rgb { transparent_color: Some((r, g, b)) } => {
+            // Truecolor tRNS is three 16-bit samples; high byte 0 since we're 8 bpc.
+            write_chunk(&mut file, b"tRNS", &[0, *r, 0, *g, 0, *b]);
+        }
+        _ => {}
+    }
+
+    write_chunk(&mut file, b"IDAT", &idat);
+    write_chunk(&mut file, b"IEND", &[]);
+
+    output.write_all(&file)
+}
+
+/// Work out the smallest colour type that can represent `page` losslessly.
+fn plan_color_type(page: &Page) -> ColorPlan {
+    let mut palette: Vec<(u8, u8, u8, u8)> = Vec::new();
+    let mut index_of: HashMap<(u8, u8, u8, u8), u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(page.width * page.height);
+    let mut fits_palette = true;
+
+    for px in page.rgba.chunks_exact(4) {
+        let key = (px[0], px[1], px[2], px[3]);
+        let idx = match index_of.get(&key) {
+            Some(&i) => i,
+            None => {
+                if palette.len() >= 256 {
+                    fits_palette = false;
+                    break;
+                }
+                let i = palette.len() as u8;
+                palette.push(key);
+                index_of.insert(key, i);
+                i
+            }
+        };
+        indices.push(idx);
+    }
+
+    if fits_palette {
+        let mut trns: Vec<u8> = palette.iter().map(|&(_, _, _, a)| a).collect();
+        while trns.last() == Some(&255) {
+            trns.pop();
+        }
+        return ColorPlan::Indexed {
+            palette: palette.iter().map(|&(r, g, b, _)| (r, g, b)).collect(),
+            trns,
+            pixel_index: indices,
+        };
+    }
+
+    // More than 256 distinct colours: see if alpha is strictly binary (the
+    // chroma-keying this tool already does only ever produces 0 or 255), in
+    // which case a single colour-key value can stand in for the whole alpha
+    // channel instead of storing it per pixel.
+    let mut transparent_rgb: Option<(u8, u8, u8)> = None;
+    let mut binary_alpha = true;
+    for px in page.rgba.chunks_exact(4) {
+        match px[3] {
+            255 => {}
+            0 => {
+                let rgb = (px[0], px[1], px[2]);
+                match transparent_rgb {
+                    None => transparent_rgb = Some(rgb),
+                    Some(existing) if existing == rgb => {}
+                    Some(_) => {
+                        binary_alpha = false;
+                        break;
+                    }
+                }
+            }
+            _ => {
+                binary_alpha = false;
+                break;
+            }
+        }
+    }
+
+    if binary_alpha {
+        // A single truecolor tRNS key only round-trips losslessly if no
+        // opaque pixel happens to share that exact RGB value - otherwise it
+        // would decode back as transparent. Fall back to storing alpha
+        // per-pixel in that case.
+        let key_collides = transparent_rgb.map_or(false, |trans| {
+            page.rgba
+                .chunks_exact(4)
+                .any(|px| px[3] == 255 && (px[0], px[1], px[2]) == trans)
+        });
+        if key_collides {
+            ColorPlan::Rgba
+        } else {
+            ColorPlan::Rgb { transparent_color: transparent_rgb }
+        }
+    } else {
+        ColorPlan::Rgba
+    }
+}
+
+/// Build the pre-filter scanline bytes for `plan`, plus the byte distance
+/// between a pixel and its left neighbour (needed by Sub/Average/Paeth).
+fn raw_rows(page: &Page, plan: &ColorPlan) -> (Vec<Vec<u8>>, usize) {
+    match plan {
+        ColorPlan::Indexed { pixel_index, .. } => (
+            pixel_index.chunks_exact(page.width).map(|r| r.to_vec()).collect(),
+            1,
+        ),
+        ColorPlan::Rgb { .. } => {
+            let rows = page
+                .rgba
+                .chunks_exact(page.width * 4)
+                .map(|row| row.chunks_exact(4).flat_map(|px| &px[0..3]).copied().collect())
+                .collect();
+            (rows, 3)
+        }
+        ColorPlan::Rgba => (
+            page.rgba.chunks_exact(page.width * 4).map(|r| r.to_vec()).collect(),
+            4,
+        ),
+    }
+}
+
+fn paeth_predictor(a: i16, b: i16, c: i16) -> u8 {
+    let p = a + b - c;
+    let pa = (p - a).abs();
+    let pb = (p - b).abs();
+    let pc = (p - c).abs();
+    if pa <= pb && pa <= pc {
+        a as u8
+    } else if pb <= pc {
+        b as u8
+    } else {
+        c as u8
+    }
+}
+
+/// Libpng's "minimum sum of absolute differences" filter heuristic: treat
+/// each filtered byte as a signed delta and sum the magnitudes.
+fn sum_abs_differences(row: &[u8]) -> u64 {
+    row.iter().map(|&b| if b < 128 { b as u64 } else { 256 - b as u64 }).sum()
+}
+
+/// Produce the filter-type byte followed by the filtered scanline, choosing
+/// whichever of the five PNG filters minimizes `sum_abs_differences` (or
+/// always "None" at level 0, the cheapest possible choice).
+fn choose_filtered_row(cur: &[u8], prev: &[u8], bpp: usize, level: u8) -> Vec<u8> {
+    if level == 0 {
+        let mut out = Vec::with_capacity(cur.len() + 1);
+        out.push(0);
+        out.extend_from_slice(cur);
+        return out;
+    }
+
+    let n = cur.len();
+    let mut candidates: [Vec<u8>; 5] = Default::default();
+    for candidate in &mut candidates {
+        *candidate = vec![0u8; n];
+    }
+    for i in 0..n {
+        let x = cur[i];
+        let a = if i >= bpp { cur[i - bpp] } else { 0 };
+        let b = if prev.is_empty() { 0 } else { prev[i] };
+        let c = if i >= bpp && !prev.is_empty() { prev[i - bpp] } else { 0 };
+
+        candidates[0][i] = x;
+        candidates[1][i] = x.wrapping_sub(a);
+        candidates[2][i] = x.wrapping_sub(b);
+        candidates[3][i] = x.wrapping_sub(((a as u16 + b as u16) / 2) as u8);
+        candidates[4][i] = x.wrapping_sub(paeth_predictor(a as i16, b as i16, c as i16));
+    }
+
+    let (best_type, best_row) = candidates
+        .into_iter()
+        .enumerate()
+        .min_by_key(|(_, row)| sum_abs_differences(row))
+        .unwrap();
+
+    let mut out = Vec::with_capacity(n + 1);
+    out.push(best_type as u8);
+    out.extend_from_slice(&best_row);
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let mut crc_input = Vec::with_capacity(4 + data.len());
+    crc_input.extend_from_slice(chunk_type);
+    crc_input.extend_from_slice(data);
+    out.extend_from_slice(&crc_input);
+    out.extend_from_slice(&crc32(&crc_input).to_be_bytes());
+}
@@ -0,0 +1,180 @@
+//! A small hand-rolled baseline-TIFF writer: just enough to archive one or
+//! more RGBA8888 pages as a multi-directory file, with a choice of
+//! compression. There's no need to pull in a full TIFF library for this -
+//! every page is written as a single strip, which is all a framebuffer dump
+//! ever needs.
+
+use std::error::Error;
+use std::io::Write;
+
+use crate::encode::{ImageEncoder, Page};
+
+#[derive(Clone, Copy)]
+pub enum TiffCompression {
+    None,
+    Deflate,
+    PackBits,
+}
+
+pub struct TiffEncoder {
+    pub compression: TiffCompression,
+}
+
+/// One IFD (directory) entry: tag, field type, value count, and either the
+/// inline value or an offset to where the value actually lives.
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    value: [u8; 4],
+}
+
+fn short_entry(tag: u16, value: u16) -> Entry {
+    let mut v = [0u8; 4];
+    v[0..2].copy_from_slice(&value.to_le_bytes());
+    Entry { tag, field_type: 3, count: 1, value: v }
+}
+
+fn long_entry(tag: u16, value: u32) -> Entry {
+    Entry { tag, field_type: 4, count: 1, value: value.to_le_bytes() }
+}
+
+fn shorts_entry(tag: u16, count: u32, offset: u32) -> Entry {
+    Entry { tag, field_type: 3, count, value: offset.to_le_bytes() }
+}
+
+fn pad_to_even(buf: &mut Vec<u8>) {
+    if buf.len() % 2 != 0 {
+        buf.push(0);
+    }
+}
+
+impl ImageEncoder for TiffEncoder {
+    fn encode(&self, mut output: Box<dyn Write>, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"II"); // little-endian byte order mark
+        file.extend_from_slice(&42u16.to_le_bytes());
+        let first_ifd_offset_pos = file.len();
+        file.extend_from_slice(&0u32.to_le_bytes()); // patched once we know it
+
+        let mut ifd_offsets = Vec::with_capacity(pages.len());
+        let mut next_ifd_field_positions = Vec::with_capacity(pages.len());
+
+        for page in pages {
+            let strip_data = compress_strip(self.compression, page);
+
+            pad_to_even(&mut file);
+            let strip_offset = file.len() as u32;
+            file.extend_from_slice(&strip_data);
+
+            pad_to_even(&mut file);
+            let bits_per_sample_offset = file.len() as u32;
+            for _ in 0..4 {
+                file.extend_from_slice(&8u16.to_le_bytes());
+            }
+
+            pad_to_even(&mut file);
+            ifd_offsets.push(file.len() as u32);
+
+            let entries = vec![
+                long_entry(256, page.width as u32),  // ImageWidth
+                long_entry(257, page.height as u32), // ImageLength
+                shorts_entry(258, 4, bits_per_sample_offset), // BitsPerSample
+                short_entry(259, compression_tag(self.compression)), // Compression
+                short_entry(262, 2),                 // PhotometricInterpretation: RGB
+                long_entry(273, strip_offset),        // StripOffsets
+                short_entry(277, 4),                  // SamplesPerPixel
+                long_entry(278, page.height as u32),  // RowsPerStrip (one strip per page)
+                long_entry(279, strip_data.len() as u32), // StripByteCounts
+                short_entry(284, 1),                  // PlanarConfiguration: chunky
+                short_entry(338, 2),                  // ExtraSamples: unassociated alpha
+            ];
+
+            file.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+            for entry in &entries {
+                file.extend_from_slice(&entry.tag.to_le_bytes());
+                file.extend_from_slice(&entry.field_type.to_le_bytes());
+                file.extend_from_slice(&entry.count.to_le_bytes());
+                file.extend_from_slice(&entry.value);
+            }
+            next_ifd_field_positions.push(file.len());
+            file.extend_from_slice(&0u32.to_le_bytes()); // next IFD offset, patched below
+        }
+
+        file[first_ifd_offset_pos..first_ifd_offset_pos + 4].copy_from_slice(&ifd_offsets[0].to_le_bytes());
+        for (i, &pos) in next_ifd_field_positions.iter().enumerate() {
+            let next = ifd_offsets.get(i + 1).copied().unwrap_or(0);
+            file[pos..pos + 4].copy_from_slice(&next.to_le_bytes());
+        }
+
+        output.write_all(&file)?;
+        Ok(())
+    }
+}
+
+fn compression_tag(compression: TiffCompression) -> u16 {
+    match compression {
+        TiffCompression::None => 1,
+        TiffCompression::Deflate => 8,
+        TiffCompression::PackBits => 32773,
+    }
+}
+
+fn compress_strip(compression: TiffCompression, page: &Page) -> Vec<u8> {
+    match compression {
+        TiffCompression::None => page.rgba.clone(),
+        TiffCompression::PackBits => {
+            let row_bytes = page.width * 4;
+            let mut out = Vec::new();
+            for row in page.rgba.chunks_exact(row_bytes) {
+                out.extend_from_slice(&pack_bits_encode(row));
+            }
+            out
+        }
+        TiffCompression::Deflate => {
+            use flate2::write::ZlibEncoder;
+            use flate2::Compression;
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&page.rgba)
+                .expect("in-memory compression cannot fail");
+            encoder.finish().expect("in-memory compression cannot fail")
+        }
+    }
+}
+
+/// Classic TIFF PackBits: a stream of (control byte, payload) packets. A
+/// non-negative control byte `n` means "copy the next `n + 1` bytes
+/// literally"; a negative one means "repeat the next byte `1 - n` times".
+/// Runs never cross scanline boundaries, matching how readers reset the
+/// decoder at the start of every row.
+fn pack_bits_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = run_length(data, i);
+        if run >= 2 {
+            out.push((1 - run as i32) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            let lit_start = i;
+            let mut j = i;
+            while j < data.len() && j - lit_start < 128 && run_length(data, j) < 2 {
+                j += 1;
+            }
+            out.push((j - lit_start - 1) as u8);
+            out.extend_from_slice(&data[lit_start..j]);
+            i = j;
+        }
+    }
+    out
+}
+
+fn run_length(data: &[u8], start: usize) -> usize {
+    let mut run = 1;
+    while start + run < data.len() && data[start + run] == data[start] && run < 128 {
+        run += 1;
+    }
+    run
+}